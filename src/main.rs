@@ -4,11 +4,19 @@ extern crate num_traits;
 
 use num_traits::FromPrimitive;
 use std::cmp::PartialEq;
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read, Write};
 
 const REG_WIDTH: usize = 2; // register width in bytes, 2 bytes = 16 bit
 
+// used by save_state()/load_state() below, wired into main() via
+// --save-state/--load-state
+const SAVE_STATE_MAGIC: &[u8; 4] = b"P86S";
+// bump whenever the byte layout Bus::save_state()/load_state() produce
+// changes, so an old snapshot is rejected instead of desyncing the read
+const SAVE_STATE_VERSION: u16 = 2;
+
 #[derive(PartialEq, Primitive)]
 enum Operation {
     Add = 0,
@@ -24,17 +32,295 @@ enum Operation {
 #[derive(Clone, Copy, Debug)]
 enum RegisterMemory {
     Register,
-    Memory,
+    Memory(Segment),
+}
+
+// segment registers, in the order the Sreg mod r/m field encodes them
+#[derive(Clone, Copy, Debug)]
+enum Segment {
+    Es,
+    Cs,
+    Ss,
+    Ds,
+}
+
+// a condition the CPU can't keep running past on its own; run() returns this
+// instead of panicking so a caller can vector through the IVT or stop cleanly.
+// DivideByZero has no raise site yet (there's no DIV opcode), kept here so
+// the vector table below is already correct once one is added
+#[derive(Debug)]
+#[allow(dead_code)]
+enum Exception {
+    InvalidOpcode(u8),
+    UnsupportedModRm(u8),
+    DivideByZero,
+    Halted,
+}
+
+impl Exception {
+    // the IVT vector real hardware would raise for this exception, if any
+    fn vector(&self) -> Option<u8> {
+        match self {
+            Exception::DivideByZero => Some(0),
+            Exception::InvalidOpcode(_) => Some(6),
+            Exception::UnsupportedModRm(_) => None,
+            Exception::Halted => None,
+        }
+    }
+}
+
+// operation width, so the ALU can tell an 8 bit op from a 16 bit one
+#[derive(Clone, Copy, PartialEq)]
+enum Width {
+    Byte,
+    Word,
+}
+
+impl Width {
+    // highest bit for this width, used for sign and overflow checks
+    fn sign_bit(self) -> u16 {
+        match self {
+            Width::Byte => 0x80,
+            Width::Word => 0x8000,
+        }
+    }
+
+    // bits that are actually significant at this width
+    fn mask(self) -> u16 {
+        match self {
+            Width::Byte => 0xff,
+            Width::Word => 0xffff,
+        }
+    }
+}
+
+// a peripheral mapped into either the physical address space or the I/O
+// port space; addresses passed in are already relative to where it's mapped
+trait Device {
+    fn read8(&mut self, address: usize) -> u8;
+    fn write8(&mut self, address: usize, value: u8);
+
+    // dumps/restores this device's internal state, so save_state()/
+    // load_state() capture more than just the RAM backing the bus
+    fn save_state(&self, f: &mut File);
+    fn load_state(&mut self, f: &mut File);
+}
+
+// plain memory, used as the bus's fallback device
+struct Ram {
+    data: [u8; 1024 * 1024], // full 1MB, 20 bit physical address space
+}
+
+impl Device for Ram {
+    fn read8(&mut self, address: usize) -> u8 {
+        self.data[address]
+    }
+
+    fn write8(&mut self, address: usize, value: u8) {
+        self.data[address] = value;
+    }
+
+    fn save_state(&self, f: &mut File) {
+        f.write_all(&self.data).unwrap();
+    }
+
+    fn load_state(&mut self, f: &mut File) {
+        f.read_exact(&mut self.data).unwrap();
+    }
+}
+
+// an 80x25 text console, mapped at physical 0x8000, that redraws the whole
+// screen every time a byte lands in it
+struct TextScreen {
+    buffer: [u8; TextScreen::WIDTH * TextScreen::HEIGHT],
+}
+
+impl TextScreen {
+    const WIDTH: usize = 80;
+    const HEIGHT: usize = 25;
+
+    fn new() -> TextScreen {
+        TextScreen {
+            buffer: [0; TextScreen::WIDTH * TextScreen::HEIGHT],
+        }
+    }
+
+    fn render(&self) {
+        for line in 0..TextScreen::HEIGHT {
+            for col in 0..TextScreen::WIDTH {
+                let byte = self.buffer[line * TextScreen::WIDTH + col];
+                let output = if byte == 0 { ' ' } else { byte as char };
+
+                print!("{}", output);
+            }
+            println!();
+        }
+    }
+}
+
+impl Device for TextScreen {
+    fn read8(&mut self, address: usize) -> u8 {
+        self.buffer[address]
+    }
+
+    fn write8(&mut self, address: usize, value: u8) {
+        self.buffer[address] = value;
+        self.render();
+    }
+
+    fn save_state(&self, f: &mut File) {
+        f.write_all(&self.buffer).unwrap();
+    }
+
+    fn load_state(&mut self, f: &mut File) {
+        f.read_exact(&mut self.buffer).unwrap();
+        self.render();
+    }
+}
+
+// a device and the range of addresses (memory or port) it claims
+struct DeviceMapping {
+    start: usize,
+    end: usize, // exclusive
+    device: Box<dyn Device>,
+}
+
+// routes reads and writes to whichever mapped device claims the address,
+// falling back to plain RAM for memory (there's no fallback for ports)
+struct Bus {
+    ram: Ram,
+    memory_devices: Vec<DeviceMapping>,
+    port_devices: Vec<DeviceMapping>,
+}
+
+impl Bus {
+    fn new() -> Bus {
+        let mut bus = Bus {
+            ram: Ram {
+                data: [0; 1024 * 1024],
+            },
+            memory_devices: Vec::new(),
+            port_devices: Vec::new(),
+        };
+
+        bus.map_memory(0x8000, TextScreen::WIDTH * TextScreen::HEIGHT, Box::new(TextScreen::new()));
+
+        bus
+    }
+
+    fn map_memory(&mut self, start: usize, len: usize, device: Box<dyn Device>) {
+        self.memory_devices.push(DeviceMapping {
+            start,
+            end: start + len,
+            device,
+        });
+    }
+
+    // no port device is mapped yet, but IN/OUT already route through this
+    // so future peripherals (a PIC, a timer, ...) just need a call here
+    #[allow(dead_code)]
+    fn map_port(&mut self, start: usize, len: usize, device: Box<dyn Device>) {
+        self.port_devices.push(DeviceMapping {
+            start,
+            end: start + len,
+            device,
+        });
+    }
+
+    // loads raw bytes straight into RAM, bypassing any mapped device
+    fn load(&mut self, offset: usize, data: &[u8]) {
+        self.ram.data[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    // dumps RAM plus every mapped device's own state, in the fixed order
+    // they're always constructed in by Bus::new(), so a restore lines up
+    // without needing per-device length prefixes
+    fn save_state(&self, f: &mut File) {
+        self.ram.save_state(f);
+
+        for mapping in &self.memory_devices {
+            mapping.device.save_state(f);
+        }
+        for mapping in &self.port_devices {
+            mapping.device.save_state(f);
+        }
+    }
+
+    fn load_state(&mut self, f: &mut File) {
+        self.ram.load_state(f);
+
+        for mapping in &mut self.memory_devices {
+            mapping.device.load_state(f);
+        }
+        for mapping in &mut self.port_devices {
+            mapping.device.load_state(f);
+        }
+    }
+
+    fn read8(&mut self, address: usize) -> u8 {
+        match Bus::claim(&mut self.memory_devices, address) {
+            Some((device, offset)) => device.read8(offset),
+            None => self.ram.read8(address),
+        }
+    }
+
+    fn write8(&mut self, address: usize, value: u8) {
+        match Bus::claim(&mut self.memory_devices, address) {
+            Some((device, offset)) => device.write8(offset, value),
+            None => self.ram.write8(address, value),
+        }
+    }
+
+    fn in8(&mut self, port: usize) -> u8 {
+        match Bus::claim(&mut self.port_devices, port) {
+            Some((device, offset)) => device.read8(offset),
+            None => 0,
+        }
+    }
+
+    fn out8(&mut self, port: usize, value: u8) {
+        if let Some((device, offset)) = Bus::claim(&mut self.port_devices, port) {
+            device.write8(offset, value);
+        }
+    }
+
+    // finds the device mapped over `address`, if any, and the address
+    // translated to be relative to where that device is mapped
+    fn claim(mappings: &mut [DeviceMapping], address: usize) -> Option<(&mut Box<dyn Device>, usize)> {
+        mappings
+            .iter_mut()
+            .find(|mapping| address >= mapping.start && address < mapping.end)
+            .map(|mapping| (&mut mapping.device, address - mapping.start))
+    }
 }
 
+// one slot of the opcode dispatch table; every opcode handler has this shape
+type OpcodeHandler = fn(&mut Cpu) -> Result<(), Exception>;
+
 struct Cpu {
-    memory: [u8; 64 * 1024],   // we only need one 64KB memory segment
+    bus: Bus,                  // RAM plus memory and port mapped devices
     ip: u16,                   // instruction pointer
     regs: [u8; 8 * REG_WIDTH], // registers
-    // flags, only need these three
-    cf: bool, // carry
-    zf: bool, // zero
-    sf: bool, // sign
+    // segment registers
+    cs: u16, // code
+    ds: u16, // data
+    ss: u16, // stack
+    es: u16, // extra
+    // set by a segment override prefix, for the next memory access only
+    segment_override: Option<Segment>,
+    // flags
+    cf: bool,   // carry
+    zf: bool,   // zero
+    sf: bool,   // sign
+    of: bool,   // overflow
+    af: bool,   // auxiliary carry
+    pf: bool,   // parity
+    intf: bool, // interrupt enable
+    // the opcode byte run() last fetched, for handlers whose register or
+    // condition is encoded in the opcode itself (e.g. 0x40..=0x47)
+    opcode: u8,
+    // dispatch table from opcode byte to handler, built once by new()
+    optable: [OpcodeHandler; 256],
 }
 
 #[derive(Debug)]
@@ -49,6 +335,11 @@ impl Cpu {
         rm: RegisterMemory::Register,
         offset: 0b000,
     };
+    // data register
+    const DX: Pointer = Pointer {
+        rm: RegisterMemory::Register,
+        offset: 0b010 * REG_WIDTH,
+    };
     // base register
     const BX: Pointer = Pointer {
         rm: RegisterMemory::Register,
@@ -72,12 +363,23 @@ impl Cpu {
 
     fn new() -> Cpu {
         let mut cpu = Cpu {
-            memory: [0; 64 * 1024],
+            bus: Bus::new(),
             ip: 0,
             regs: [0; 16],
+            cs: 0,
+            ds: 0,
+            ss: 0,
+            es: 0,
+            segment_override: None,
             cf: false,
             zf: false,
             sf: false,
+            of: false,
+            af: false,
+            pf: false,
+            intf: false,
+            opcode: 0,
+            optable: Cpu::build_optable(),
         };
 
         cpu.write16(&Cpu::SP, 0x100);
@@ -85,9 +387,150 @@ impl Cpu {
         cpu
     }
 
+    // builds the opcode dispatch table once, up front, rather than
+    // re-deciding per instruction which handler an opcode maps to
+    fn build_optable() -> [OpcodeHandler; 256] {
+        let mut table: [OpcodeHandler; 256] = [Cpu::op_invalid; 256];
+
+        table[0x01] = Cpu::op_add_rm16_reg16;
+        table[0x09] = Cpu::op_and_rm16_reg16;
+        table[0x19] = Cpu::op_sbb_rm16_reg16;
+        table[0x29] = Cpu::op_sub_rm16_reg16;
+        table[0x31] = Cpu::op_xor_rm16_reg16;
+        table[0x39] = Cpu::op_cmp_rm16_reg16;
+
+        table[0x04] = Cpu::op_add_al_imm8;
+        table[0x20] = Cpu::op_and_rm8_reg8;
+        table[0x3c] = Cpu::op_cmp_al_imm8;
+
+        table[0x40..=0x47].fill(Cpu::op_inc_reg16);
+        table[0x48..=0x4f].fill(Cpu::op_dec_reg16);
+        table[0x50..=0x57].fill(Cpu::op_push_reg16);
+        table[0x58..=0x5f].fill(Cpu::op_pop_reg16);
+
+        table[0x70] = Cpu::op_jo;
+        table[0x71] = Cpu::op_jno;
+        table[0x72] = Cpu::op_jc;
+        table[0x74] = Cpu::op_jz;
+        table[0x75] = Cpu::op_jnz;
+        table[0x76] = Cpu::op_jbe;
+        table[0x77] = Cpu::op_ja;
+        table[0x78] = Cpu::op_js;
+        table[0x79] = Cpu::op_jns;
+        table[0x7a] = Cpu::op_jp;
+        table[0x7b] = Cpu::op_jnp;
+        table[0x7c] = Cpu::op_jl;
+        table[0x7d] = Cpu::op_jge;
+        table[0x7e] = Cpu::op_jle;
+        table[0x7f] = Cpu::op_jg;
+
+        table[0x80] = Cpu::op_alu_rm8_imm8;
+        table[0x81] = Cpu::op_alu_rm16_imm16;
+        table[0x83] = Cpu::op_alu_rm16_imm8;
+        table[0x86] = Cpu::op_xchg_rm8_reg8;
+        table[0x88] = Cpu::op_mov_rm8_reg8;
+        table[0x89] = Cpu::op_mov_rm16_reg16;
+        table[0x8a] = Cpu::op_mov_reg8_rm8;
+        table[0x8b] = Cpu::op_mov_reg16_rm16;
+        table[0x8c] = Cpu::op_mov_rm16_sreg;
+        table[0x8e] = Cpu::op_mov_sreg_rm16;
+        table[0x90] = Cpu::op_nop;
+
+        table[0x91..=0x97].fill(Cpu::op_xchg_ax_reg16);
+
+        table[0x9c] = Cpu::op_pushf;
+        table[0x9d] = Cpu::op_popf;
+
+        table[0xb0..=0xb7].fill(Cpu::op_mov_reg8_imm8);
+        table[0xb8..=0xbf].fill(Cpu::op_mov_reg16_imm16);
+
+        table[0xc3] = Cpu::op_ret;
+        table[0xc7] = Cpu::op_mov_rm16_imm16;
+        table[0xcc] = Cpu::op_int3;
+        table[0xcd] = Cpu::op_int;
+        table[0xce] = Cpu::op_into;
+        table[0xcf] = Cpu::op_iret;
+
+        table[0xe4] = Cpu::op_in_al_imm8;
+        table[0xe6] = Cpu::op_out_imm8_al;
+        table[0xe8] = Cpu::op_call_rel16;
+        table[0xea] = Cpu::op_jmp_far;
+        table[0xeb] = Cpu::op_jmp_short;
+        table[0xec] = Cpu::op_in_al_dx;
+        table[0xee] = Cpu::op_out_dx_al;
+
+        table[0xf4] = Cpu::op_hlt;
+        table[0xf9] = Cpu::op_stc;
+        table[0xfa] = Cpu::op_cli;
+        table[0xfb] = Cpu::op_sti;
+        table[0xfe] = Cpu::op_inc_dec_rm8;
+
+        table
+    }
+
     fn load_program(&mut self, filename: &str) {
         let mut f = File::open(filename).unwrap();
-        let _ = f.read(&mut self.memory);
+        let mut program = Vec::new();
+        f.read_to_end(&mut program).unwrap();
+
+        self.bus.load(0, &program);
+    }
+
+    // dumps the whole machine (bus memory and devices, ip, registers,
+    // segments, flags) to a single file behind a versioned header, so a
+    // run can be frozen and resumed byte-for-byte with load_state()
+    fn save_state(&self, path: &str) {
+        let mut f = File::create(path).unwrap();
+
+        f.write_all(SAVE_STATE_MAGIC).unwrap();
+        f.write_all(&SAVE_STATE_VERSION.to_le_bytes()).unwrap();
+
+        self.bus.save_state(&mut f);
+        f.write_all(&self.ip.to_le_bytes()).unwrap();
+        f.write_all(&self.regs).unwrap();
+
+        f.write_all(&self.cs.to_le_bytes()).unwrap();
+        f.write_all(&self.ds.to_le_bytes()).unwrap();
+        f.write_all(&self.ss.to_le_bytes()).unwrap();
+        f.write_all(&self.es.to_le_bytes()).unwrap();
+
+        f.write_all(&self.flags_to_u16().to_le_bytes()).unwrap();
+    }
+
+    fn load_state(&mut self, path: &str) {
+        let mut f = File::open(path).unwrap();
+
+        let mut magic = [0; 4];
+        f.read_exact(&mut magic).unwrap();
+        assert_eq!(&magic, SAVE_STATE_MAGIC, "not a partial8086 save state");
+
+        let version = Cpu::read_state_u16(&mut f);
+        assert_eq!(
+            version, SAVE_STATE_VERSION,
+            "unsupported save state version {}",
+            version
+        );
+
+        self.bus.load_state(&mut f);
+        self.ip = Cpu::read_state_u16(&mut f);
+        f.read_exact(&mut self.regs).unwrap();
+
+        self.cs = Cpu::read_state_u16(&mut f);
+        self.ds = Cpu::read_state_u16(&mut f);
+        self.ss = Cpu::read_state_u16(&mut f);
+        self.es = Cpu::read_state_u16(&mut f);
+
+        let flags = Cpu::read_state_u16(&mut f);
+        self.flags_from_u16(flags);
+
+        self.segment_override = None;
+    }
+
+    fn read_state_u16(f: &mut File) -> u16 {
+        let mut buf = [0; 2];
+        f.read_exact(&mut buf).unwrap();
+
+        u16::from_le_bytes(buf)
     }
 
     fn get_register(offset: u8) -> Pointer {
@@ -97,257 +540,575 @@ impl Cpu {
         }
     }
 
-    fn run(&mut self) -> bool {
-        let opcode = self.read_instr8();
+    fn run(&mut self) -> Result<(), Exception> {
+        self.segment_override = None;
+
+        let mut opcode = self.read_instr8();
+
+        while let Some(segment) = Cpu::segment_override_prefix(opcode) {
+            self.segment_override = Some(segment);
+            opcode = self.read_instr8();
+        }
 
         //println!("opcode {:#x} at {:#x}", opcode, self.ip - 1);
 
-        match opcode {
-            0x1 | 0x9 | 0x19 | 0x29 | 0x31 | 0x39 => {
-                // 	16 bit register to 16 bit r/m
-                let operation = match opcode {
-                    0x1 => Operation::Add,
-                    0x9 => Operation::And,
-                    0x19 => Operation::Sbb,
-                    0x29 => Operation::Sub,
-                    0x31 => Operation::Xor,
-                    0x39 => Operation::Cmp,
-                    _ => unreachable!(),
-                };
-
-                let (rm, rg) = self.read_mod_rm();
-                let rg = Cpu::get_register(rg);
-
-                let op1 = self.read16(&rm);
-                let op2 = self.read16(&rg);
-
-                let result = self.alu(operation, op1, op2);
-                self.write16(&rm, result);
-            }
-            0x4 => {
-                // ADD 8 bit immediate to register AL
-                let op1 = Cpu::sign_extend(self.read8(&Cpu::AX));
-                let op2 = Cpu::sign_extend(self.read_instr8());
+        self.opcode = opcode;
 
-                let result = self.alu(Operation::Add, op1, op2);
-                self.write8(&Cpu::AX, result as u8);
-            }
-            0x20 => {
-                // AND 8 bit register, 8 bit r/m
-                let (rm, rg) = self.read_mod_rm();
-                let rg = Cpu::get_register(rg);
+        self.optable[opcode as usize](self)
+    }
 
-                let op1 = Cpu::sign_extend(self.read8(&rm));
-                let op2 = Cpu::sign_extend(self.read8(&rg));
+    // ---------------------------------------
+    // opcode handlers, indexed by the optable
+    // ---------------------------------------
 
-                let result = self.alu(Operation::And, op1, op2);
-                self.write8(&rm, result as u8);
-            }
-            0x3c => {
-                // compare 8 bit immediate to register AL
-                let op1 = Cpu::sign_extend(self.read8(&Cpu::AX));
-                let op2 = Cpu::sign_extend(self.read_instr8());
+    fn alu_rm16_reg16(&mut self, operation: Operation) -> Result<(), Exception> {
+        // 	16 bit register to 16 bit r/m
+        let (rm, rg) = self.read_mod_rm()?;
+        let rg = Cpu::get_register(rg);
 
-                self.alu(Operation::Cmp, op1, op2);
-            }
-            0x40..=0x47 => {
-                // increment 16 bit register
-                let rg = Cpu::get_register(opcode);
-                let value = self.read16(&rg).wrapping_add(1);
+        let op1 = self.read16(&rm);
+        let op2 = self.read16(&rg);
 
-                self.set_flags(&value);
-                self.write16(&rg, value);
-            }
-            0x48..=0x4f => {
-                // decrement 16 bit register
-                let rg = Cpu::get_register(opcode);
-                let value = self.read16(&rg).wrapping_sub(1);
+        let result = self.alu(operation, op1, op2, Width::Word);
+        self.write16(&rm, result);
 
-                self.set_flags(&value);
-                self.write16(&rg, value);
-            }
-            0x50..=0x57 => {
-                // push 16 bit register
-                let rg = Cpu::get_register(opcode);
-                let value = self.read16(&rg);
+        Ok(())
+    }
 
-                self.push16(value);
-            }
-            0x58..=0x5f => {
-                // pop 16 bit register
-                let rg = Cpu::get_register(opcode);
-                let value = self.pop16();
-                self.write16(&rg, value);
-            }
-            0x72 => {
-                // jump short if carry
-                self.jump_short(self.cf);
-            }
-            0x74 => {
-                // jump short if zero
-                self.jump_short(self.zf);
-            }
-            0x75 => {
-                // jump short if not zero
-                self.jump_short(!self.zf);
-            }
-            0x76 => {
-                // jump short if below or equal
-                self.jump_short(self.cf || self.zf);
-            }
-            0x77 => {
-                // jump short if not below or equal
-                self.jump_short(!self.cf && !self.zf);
-            }
-            0x79 => {
-                // jump short if not sign
-                self.jump_short(!self.sf);
-            }
-            0x80 => {
-                // 8 bit arithmetic
-                let (rm, operation) = self.read_mod_rm();
-                let op1 = Cpu::sign_extend(self.read8(&rm));
-                let op2 = Cpu::sign_extend(self.read_instr8());
-
-                let result = self.alu(Operation::from_u8(operation).unwrap(), op1, op2);
-                self.write8(&rm, result as u8);
-            }
-            0x81 => {
-                // 16 bit arithmetic
-                let (rm, operation) = self.read_mod_rm();
-                let op1 = self.read16(&rm);
-                let op2 = self.read_instr16();
-
-                let result = self.alu(Operation::from_u8(operation).unwrap(), op1, op2);
-                self.write16(&rm, result);
-            }
-            0x83 => {
-                // 16 bit / 8 bit arithmetic
-                let (rm, operation) = self.read_mod_rm();
-                let op1 = self.read16(&rm);
-                let op2 = Cpu::sign_extend(self.read_instr8());
-
-                let result = self.alu(Operation::from_u8(operation).unwrap(), op1, op2);
-                self.write16(&rm, result);
-            }
-            0x86 => {
-                // exchange 8 bit register with 8 bit r/m
-                let (rm, rg) = self.read_mod_rm();
-                let rg = Cpu::get_register(rg);
+    fn op_add_rm16_reg16(&mut self) -> Result<(), Exception> {
+        self.alu_rm16_reg16(Operation::Add)
+    }
 
-                let a = self.read8(&rm);
-                let b = self.read8(&rg);
+    fn op_and_rm16_reg16(&mut self) -> Result<(), Exception> {
+        self.alu_rm16_reg16(Operation::And)
+    }
 
-                self.write8(&rm, b);
-                self.write8(&rg, a);
-            }
-            0x88 => {
-                // move 8 bit register to 8 bit r/m
-                let (op1, rg) = self.read_mod_rm();
-                let rg = Cpu::get_register(rg);
-                let value = self.read8(&rg);
+    fn op_sbb_rm16_reg16(&mut self) -> Result<(), Exception> {
+        self.alu_rm16_reg16(Operation::Sbb)
+    }
 
-                self.write8(&op1, value);
-            }
-            0x89 => {
-                // move 16 bit register to 16 bit r/m
-                let (op1, rg) = self.read_mod_rm();
-                let rg = Cpu::get_register(rg);
-                let value = self.read16(&rg);
+    fn op_sub_rm16_reg16(&mut self) -> Result<(), Exception> {
+        self.alu_rm16_reg16(Operation::Sub)
+    }
 
-                self.write16(&op1, value);
-            }
-            0x8a => {
-                // move 8 bit r/m to 8 bit register
-                let (op1, rg) = self.read_mod_rm();
-                let rg = Cpu::get_register(rg);
-                let value = self.read8(&op1);
+    fn op_xor_rm16_reg16(&mut self) -> Result<(), Exception> {
+        self.alu_rm16_reg16(Operation::Xor)
+    }
 
-                self.write8(&rg, value);
-            }
-            0x8b => {
-                // move 16 bit r/m to 16 bit register
-                let (op1, rg) = self.read_mod_rm();
-                let rg = Cpu::get_register(rg);
-                let value = self.read16(&op1);
+    fn op_cmp_rm16_reg16(&mut self) -> Result<(), Exception> {
+        self.alu_rm16_reg16(Operation::Cmp)
+    }
 
-                self.write16(&rg, value);
-            }
-            0x90 => {
-                // NOP
-            }
-            0x91..=0x97 => {
-                // exchange 16 bit register with register AX
-                let rg = Cpu::get_register(opcode);
+    fn op_add_al_imm8(&mut self) -> Result<(), Exception> {
+        // ADD 8 bit immediate to register AL
+        let op1 = Cpu::sign_extend(self.read8(&Cpu::AX));
+        let op2 = Cpu::sign_extend(self.read_instr8());
 
-                let a = self.read16(&Cpu::AX);
-                let b = self.read16(&rg);
+        let result = self.alu(Operation::Add, op1, op2, Width::Byte);
+        self.write8(&Cpu::AX, result as u8);
 
-                self.write16(&Cpu::AX, b);
-                self.write16(&rg, a);
-            }
-            0xb0..=0xb7 => {
-                // move 8 bit immediate to 8 bit register
-                let rg = Cpu::get_register(opcode);
-                let value = self.read_instr8() as u16;
+        Ok(())
+    }
 
-                self.write16(&rg, value);
-            }
-            0xb8..=0xbf => {
-                // move 16 bit immediate to 16 bit register
-                let rg = Cpu::get_register(opcode);
-                let value = self.read_instr16();
+    fn op_and_rm8_reg8(&mut self) -> Result<(), Exception> {
+        // AND 8 bit register, 8 bit r/m
+        let (rm, rg) = self.read_mod_rm()?;
+        let rg = Cpu::get_register(rg);
 
-                self.write16(&rg, value);
-            }
-            0xc3 => {
-                // return near
-                self.ip = self.pop16();
-            }
-            0xc7 => {
-                // move 16 bit immediate to 16 bit r/m
-                let (rm, _) = self.read_mod_rm();
-                let value = self.read_instr16();
+        let op1 = Cpu::sign_extend(self.read8(&rm));
+        let op2 = Cpu::sign_extend(self.read8(&rg));
 
-                self.write16(&rm, value);
-            }
-            0xe8 => {
-                // call relative
-                let offset = self.read_instr16();
-                self.push16(self.ip);
-                self.ip = self.ip.wrapping_add(offset);
-            }
-            0xeb => {
-                // jump short relative
-                self.jump_short(true);
-            }
-            0xf4 => {
-                // halt
-                return false;
-            }
-            0xf9 => {
-                // set carry flag
-                self.cf = true;
-            }
-            0xfe => {
-                // increment | decrement 8 bit r/m
-                let (rm, md) = self.read_mod_rm();
-                let value = match md {
-                    0 => self.read8(&rm).wrapping_add(1),
-                    1 => self.read8(&rm).wrapping_sub(1),
-                    _ => unreachable!(),
-                };
-
-                self.set_flags(&Cpu::sign_extend(value));
-                self.write8(&rm, value);
-            }
-            _ => {
-                println!("unsupported opcode {:#x}", opcode);
-                return false;
-            }
+        let result = self.alu(Operation::And, op1, op2, Width::Byte);
+        self.write8(&rm, result as u8);
+
+        Ok(())
+    }
+
+    fn op_cmp_al_imm8(&mut self) -> Result<(), Exception> {
+        // compare 8 bit immediate to register AL
+        let op1 = Cpu::sign_extend(self.read8(&Cpu::AX));
+        let op2 = Cpu::sign_extend(self.read_instr8());
+
+        self.alu(Operation::Cmp, op1, op2, Width::Byte);
+
+        Ok(())
+    }
+
+    fn op_inc_reg16(&mut self) -> Result<(), Exception> {
+        // increment 16 bit register
+        let rg = Cpu::get_register(self.opcode);
+        let op1 = self.read16(&rg);
+        let value = op1.wrapping_add(1);
+
+        self.set_incdec_flags(op1, value, Width::Word, true);
+        self.write16(&rg, value);
+
+        Ok(())
+    }
+
+    fn op_dec_reg16(&mut self) -> Result<(), Exception> {
+        // decrement 16 bit register
+        let rg = Cpu::get_register(self.opcode);
+        let op1 = self.read16(&rg);
+        let value = op1.wrapping_sub(1);
+
+        self.set_incdec_flags(op1, value, Width::Word, false);
+        self.write16(&rg, value);
+
+        Ok(())
+    }
+
+    fn op_push_reg16(&mut self) -> Result<(), Exception> {
+        // push 16 bit register
+        let rg = Cpu::get_register(self.opcode);
+        let value = self.read16(&rg);
+
+        self.push16(value);
+
+        Ok(())
+    }
+
+    fn op_pop_reg16(&mut self) -> Result<(), Exception> {
+        // pop 16 bit register
+        let rg = Cpu::get_register(self.opcode);
+        let value = self.pop16();
+        self.write16(&rg, value);
+
+        Ok(())
+    }
+
+    fn op_jo(&mut self) -> Result<(), Exception> {
+        // jump short if overflow
+        self.jump_short(self.of);
+
+        Ok(())
+    }
+
+    fn op_jno(&mut self) -> Result<(), Exception> {
+        // jump short if not overflow
+        self.jump_short(!self.of);
+
+        Ok(())
+    }
+
+    fn op_jc(&mut self) -> Result<(), Exception> {
+        // jump short if carry
+        self.jump_short(self.cf);
+
+        Ok(())
+    }
+
+    fn op_jz(&mut self) -> Result<(), Exception> {
+        // jump short if zero
+        self.jump_short(self.zf);
+
+        Ok(())
+    }
+
+    fn op_jnz(&mut self) -> Result<(), Exception> {
+        // jump short if not zero
+        self.jump_short(!self.zf);
+
+        Ok(())
+    }
+
+    fn op_jbe(&mut self) -> Result<(), Exception> {
+        // jump short if below or equal
+        self.jump_short(self.cf || self.zf);
+
+        Ok(())
+    }
+
+    fn op_ja(&mut self) -> Result<(), Exception> {
+        // jump short if not below or equal
+        self.jump_short(!self.cf && !self.zf);
+
+        Ok(())
+    }
+
+    fn op_js(&mut self) -> Result<(), Exception> {
+        // jump short if sign
+        self.jump_short(self.sf);
+
+        Ok(())
+    }
+
+    fn op_jns(&mut self) -> Result<(), Exception> {
+        // jump short if not sign
+        self.jump_short(!self.sf);
+
+        Ok(())
+    }
+
+    fn op_jp(&mut self) -> Result<(), Exception> {
+        // jump short if parity
+        self.jump_short(self.pf);
+
+        Ok(())
+    }
+
+    fn op_jnp(&mut self) -> Result<(), Exception> {
+        // jump short if not parity
+        self.jump_short(!self.pf);
+
+        Ok(())
+    }
+
+    fn op_jl(&mut self) -> Result<(), Exception> {
+        // jump short if less
+        self.jump_short(self.sf != self.of);
+
+        Ok(())
+    }
+
+    fn op_jge(&mut self) -> Result<(), Exception> {
+        // jump short if greater or equal
+        self.jump_short(self.sf == self.of);
+
+        Ok(())
+    }
+
+    fn op_jle(&mut self) -> Result<(), Exception> {
+        // jump short if less or equal
+        self.jump_short(self.sf != self.of || self.zf);
+
+        Ok(())
+    }
+
+    fn op_jg(&mut self) -> Result<(), Exception> {
+        // jump short if greater
+        self.jump_short(self.sf == self.of && !self.zf);
+
+        Ok(())
+    }
+
+    fn op_alu_rm8_imm8(&mut self) -> Result<(), Exception> {
+        // 8 bit arithmetic
+        let (rm, operation) = self.read_mod_rm()?;
+        let op1 = Cpu::sign_extend(self.read8(&rm));
+        let op2 = Cpu::sign_extend(self.read_instr8());
+
+        let result = self.alu(Operation::from_u8(operation).unwrap(), op1, op2, Width::Byte);
+        self.write8(&rm, result as u8);
+
+        Ok(())
+    }
+
+    fn op_alu_rm16_imm16(&mut self) -> Result<(), Exception> {
+        // 16 bit arithmetic
+        let (rm, operation) = self.read_mod_rm()?;
+        let op1 = self.read16(&rm);
+        let op2 = self.read_instr16();
+
+        let result = self.alu(Operation::from_u8(operation).unwrap(), op1, op2, Width::Word);
+        self.write16(&rm, result);
+
+        Ok(())
+    }
+
+    fn op_alu_rm16_imm8(&mut self) -> Result<(), Exception> {
+        // 16 bit / 8 bit arithmetic
+        let (rm, operation) = self.read_mod_rm()?;
+        let op1 = self.read16(&rm);
+        let op2 = Cpu::sign_extend(self.read_instr8());
+
+        let result = self.alu(Operation::from_u8(operation).unwrap(), op1, op2, Width::Word);
+        self.write16(&rm, result);
+
+        Ok(())
+    }
+
+    fn op_xchg_rm8_reg8(&mut self) -> Result<(), Exception> {
+        // exchange 8 bit register with 8 bit r/m
+        let (rm, rg) = self.read_mod_rm()?;
+        let rg = Cpu::get_register(rg);
+
+        let a = self.read8(&rm);
+        let b = self.read8(&rg);
+
+        self.write8(&rm, b);
+        self.write8(&rg, a);
+
+        Ok(())
+    }
+
+    fn op_mov_rm8_reg8(&mut self) -> Result<(), Exception> {
+        // move 8 bit register to 8 bit r/m
+        let (op1, rg) = self.read_mod_rm()?;
+        let rg = Cpu::get_register(rg);
+        let value = self.read8(&rg);
+
+        self.write8(&op1, value);
+
+        Ok(())
+    }
+
+    fn op_mov_rm16_reg16(&mut self) -> Result<(), Exception> {
+        // move 16 bit register to 16 bit r/m
+        let (op1, rg) = self.read_mod_rm()?;
+        let rg = Cpu::get_register(rg);
+        let value = self.read16(&rg);
+
+        self.write16(&op1, value);
+
+        Ok(())
+    }
+
+    fn op_mov_reg8_rm8(&mut self) -> Result<(), Exception> {
+        // move 8 bit r/m to 8 bit register
+        let (op1, rg) = self.read_mod_rm()?;
+        let rg = Cpu::get_register(rg);
+        let value = self.read8(&op1);
+
+        self.write8(&rg, value);
+
+        Ok(())
+    }
+
+    fn op_mov_reg16_rm16(&mut self) -> Result<(), Exception> {
+        // move 16 bit r/m to 16 bit register
+        let (op1, rg) = self.read_mod_rm()?;
+        let rg = Cpu::get_register(rg);
+        let value = self.read16(&op1);
+
+        self.write16(&rg, value);
+
+        Ok(())
+    }
+
+    fn op_mov_rm16_sreg(&mut self) -> Result<(), Exception> {
+        // move segment register to 16 bit r/m
+        let (rm, sreg) = self.read_mod_rm()?;
+        let value = self.segment_value(Cpu::get_segment(sreg));
+
+        self.write16(&rm, value);
+
+        Ok(())
+    }
+
+    fn op_mov_sreg_rm16(&mut self) -> Result<(), Exception> {
+        // move 16 bit r/m to segment register
+        let (rm, sreg) = self.read_mod_rm()?;
+        let value = self.read16(&rm);
+
+        self.set_segment_value(Cpu::get_segment(sreg), value);
+
+        Ok(())
+    }
+
+    fn op_nop(&mut self) -> Result<(), Exception> {
+        Ok(())
+    }
+
+    fn op_xchg_ax_reg16(&mut self) -> Result<(), Exception> {
+        // exchange 16 bit register with register AX
+        let rg = Cpu::get_register(self.opcode);
+
+        let a = self.read16(&Cpu::AX);
+        let b = self.read16(&rg);
+
+        self.write16(&Cpu::AX, b);
+        self.write16(&rg, a);
+
+        Ok(())
+    }
+
+    fn op_pushf(&mut self) -> Result<(), Exception> {
+        // push flags
+        let flags = self.flags_to_u16();
+        self.push16(flags);
+
+        Ok(())
+    }
+
+    fn op_popf(&mut self) -> Result<(), Exception> {
+        // pop flags
+        let flags = self.pop16();
+        self.flags_from_u16(flags);
+
+        Ok(())
+    }
+
+    fn op_mov_reg8_imm8(&mut self) -> Result<(), Exception> {
+        // move 8 bit immediate to 8 bit register
+        let rg = Cpu::get_register(self.opcode);
+        let value = self.read_instr8() as u16;
+
+        self.write16(&rg, value);
+
+        Ok(())
+    }
+
+    fn op_mov_reg16_imm16(&mut self) -> Result<(), Exception> {
+        // move 16 bit immediate to 16 bit register
+        let rg = Cpu::get_register(self.opcode);
+        let value = self.read_instr16();
+
+        self.write16(&rg, value);
+
+        Ok(())
+    }
+
+    fn op_ret(&mut self) -> Result<(), Exception> {
+        // return near
+        self.ip = self.pop16();
+
+        Ok(())
+    }
+
+    fn op_mov_rm16_imm16(&mut self) -> Result<(), Exception> {
+        // move 16 bit immediate to 16 bit r/m
+        let (rm, _) = self.read_mod_rm()?;
+        let value = self.read_instr16();
+
+        self.write16(&rm, value);
+
+        Ok(())
+    }
+
+    fn op_int3(&mut self) -> Result<(), Exception> {
+        // breakpoint interrupt
+        self.raise_interrupt(3);
+
+        Ok(())
+    }
+
+    fn op_int(&mut self) -> Result<(), Exception> {
+        // software interrupt
+        let vector = self.read_instr8();
+        self.raise_interrupt(vector);
+
+        Ok(())
+    }
+
+    fn op_into(&mut self) -> Result<(), Exception> {
+        // interrupt on overflow
+        if self.of {
+            self.raise_interrupt(4);
+        }
+
+        Ok(())
+    }
+
+    fn op_iret(&mut self) -> Result<(), Exception> {
+        // interrupt return
+        self.ip = self.pop16();
+        self.cs = self.pop16();
+        let flags = self.pop16();
+        self.flags_from_u16(flags);
+
+        Ok(())
+    }
+
+    fn op_in_al_imm8(&mut self) -> Result<(), Exception> {
+        // in al, imm8
+        let port = self.read_instr8();
+        let value = self.bus.in8(port as usize);
+        self.write8(&Cpu::AX, value);
+
+        Ok(())
+    }
+
+    fn op_out_imm8_al(&mut self) -> Result<(), Exception> {
+        // out imm8, al
+        let port = self.read_instr8();
+        let value = self.read8(&Cpu::AX);
+        self.bus.out8(port as usize, value);
+
+        Ok(())
+    }
+
+    fn op_call_rel16(&mut self) -> Result<(), Exception> {
+        // call relative
+        let offset = self.read_instr16();
+        self.push16(self.ip);
+        self.ip = self.ip.wrapping_add(offset);
+
+        Ok(())
+    }
+
+    fn op_jmp_far(&mut self) -> Result<(), Exception> {
+        // jump far, sets both ip and cs
+        let ip = self.read_instr16();
+        let cs = self.read_instr16();
+
+        self.ip = ip;
+        self.cs = cs;
+
+        Ok(())
+    }
+
+    fn op_jmp_short(&mut self) -> Result<(), Exception> {
+        // jump short relative
+        self.jump_short(true);
+
+        Ok(())
+    }
+
+    fn op_in_al_dx(&mut self) -> Result<(), Exception> {
+        // in al, dx
+        let port = self.read16(&Cpu::DX);
+        let value = self.bus.in8(port as usize);
+        self.write8(&Cpu::AX, value);
+
+        Ok(())
+    }
+
+    fn op_out_dx_al(&mut self) -> Result<(), Exception> {
+        // out dx, al
+        let port = self.read16(&Cpu::DX);
+        let value = self.read8(&Cpu::AX);
+        self.bus.out8(port as usize, value);
+
+        Ok(())
+    }
+
+    fn op_hlt(&mut self) -> Result<(), Exception> {
+        // halt
+        Err(Exception::Halted)
+    }
+
+    fn op_stc(&mut self) -> Result<(), Exception> {
+        // set carry flag
+        self.cf = true;
+
+        Ok(())
+    }
+
+    fn op_cli(&mut self) -> Result<(), Exception> {
+        // clear interrupt flag
+        self.intf = false;
+
+        Ok(())
+    }
+
+    fn op_sti(&mut self) -> Result<(), Exception> {
+        // set interrupt flag
+        self.intf = true;
+
+        Ok(())
+    }
+
+    fn op_inc_dec_rm8(&mut self) -> Result<(), Exception> {
+        // increment | decrement 8 bit r/m
+        let (rm, md) = self.read_mod_rm()?;
+        let op1 = self.read8(&rm);
+        let is_inc = md == 0;
+        let value = match md {
+            0 => op1.wrapping_add(1),
+            1 => op1.wrapping_sub(1),
+            _ => unreachable!(),
         };
 
-        true
+        self.set_incdec_flags(op1 as u16, value as u16, Width::Byte, is_inc);
+        self.write8(&rm, value);
+
+        Ok(())
+    }
+
+    // default handler for unused optable slots
+    fn op_invalid(&mut self) -> Result<(), Exception> {
+        Err(Exception::InvalidOpcode(self.opcode))
     }
 
     // ------------------------------------
@@ -355,7 +1116,7 @@ impl Cpu {
     // ------------------------------------
 
     fn read_instr8(&mut self) -> u8 {
-        let read = self.memory[self.ip as usize];
+        let read = self.bus.read8(Cpu::physical_address(self.cs, self.ip));
         self.ip += 1;
 
         read
@@ -375,7 +1136,11 @@ impl Cpu {
     fn read8(&mut self, pointer: &Pointer) -> u8 {
         match pointer.rm {
             RegisterMemory::Register => self.regs[pointer.offset],
-            RegisterMemory::Memory => self.memory[pointer.offset],
+            RegisterMemory::Memory(segment) => {
+                let segment = self.segment_value(segment);
+                self.bus
+                    .read8(Cpu::physical_address(segment, pointer.offset as u16))
+            }
         }
     }
 
@@ -394,7 +1159,11 @@ impl Cpu {
     fn write8(&mut self, pointer: &Pointer, value: u8) {
         match pointer.rm {
             RegisterMemory::Register => self.regs[pointer.offset] = value,
-            RegisterMemory::Memory => self.memory[pointer.offset] = value,
+            RegisterMemory::Memory(segment) => {
+                let segment = self.segment_value(segment);
+                self.bus
+                    .write8(Cpu::physical_address(segment, pointer.offset as u16), value)
+            }
         };
     }
 
@@ -415,7 +1184,7 @@ impl Cpu {
 
     fn push8(&mut self, value: u8) {
         let sp = self.read16(&Cpu::SP);
-        self.memory[sp as usize] = value;
+        self.bus.write8(Cpu::physical_address(self.ss, sp), value);
         self.write16(&Cpu::SP, sp - 1);
     }
 
@@ -428,7 +1197,7 @@ impl Cpu {
 
     fn pop8(&mut self) -> u8 {
         let sp = self.read16(&Cpu::SP) + 1;
-        let value = self.memory[sp as usize];
+        let value = self.bus.read8(Cpu::physical_address(self.ss, sp));
         self.write16(&Cpu::SP, sp);
 
         value
@@ -457,9 +1226,61 @@ impl Cpu {
         (a, b)
     }
 
+    // ----------------------------------
+    // segmentation / 20 bit address translation
+    // ----------------------------------
+
+    // (segment << 4) + offset, wrapped to the 20 bit physical address space
+    fn physical_address(segment: u16, offset: u16) -> usize {
+        (((segment as u32) << 4) + offset as u32) as usize & 0xfffff
+    }
+
+    fn segment_value(&self, segment: Segment) -> u16 {
+        match segment {
+            Segment::Es => self.es,
+            Segment::Cs => self.cs,
+            Segment::Ss => self.ss,
+            Segment::Ds => self.ds,
+        }
+    }
+
+    fn set_segment_value(&mut self, segment: Segment, value: u16) {
+        match segment {
+            Segment::Es => self.es = value,
+            Segment::Cs => self.cs = value,
+            Segment::Ss => self.ss = value,
+            Segment::Ds => self.ds = value,
+        };
+    }
+
+    fn get_segment(reg: u8) -> Segment {
+        match reg & 0b11 {
+            0b00 => Segment::Es,
+            0b01 => Segment::Cs,
+            0b10 => Segment::Ss,
+            0b11 => Segment::Ds,
+            _ => unreachable!(),
+        }
+    }
+
+    // the segment a memory operand uses, unless a prefix overrides it
+    fn effective_segment(&self, default: Segment) -> Segment {
+        self.segment_override.unwrap_or(default)
+    }
+
+    fn segment_override_prefix(opcode: u8) -> Option<Segment> {
+        match opcode {
+            0x26 => Some(Segment::Es),
+            0x2e => Some(Segment::Cs),
+            0x36 => Some(Segment::Ss),
+            0x3e => Some(Segment::Ds),
+            _ => None,
+        }
+    }
+
     // -------------------------
 
-    fn read_mod_rm(&mut self) -> (Pointer, u8) {
+    fn read_mod_rm(&mut self) -> Result<(Pointer, u8), Exception> {
         let mod_rm = self.read_instr8();
 
         let md = mod_rm >> 6; // modifier
@@ -474,7 +1295,7 @@ impl Cpu {
                     let offset = bx.wrapping_add(di) as usize;
 
                     Pointer {
-                        rm: RegisterMemory::Memory,
+                        rm: RegisterMemory::Memory(self.effective_segment(Segment::Ds)),
                         offset,
                     }
                 }
@@ -482,7 +1303,7 @@ impl Cpu {
                     let offset = self.read_instr16() as usize;
 
                     Pointer {
-                        rm: RegisterMemory::Memory,
+                        rm: RegisterMemory::Memory(self.effective_segment(Segment::Ds)),
                         offset,
                     }
                 }
@@ -490,11 +1311,11 @@ impl Cpu {
                     let offset = self.read16(&Cpu::BX) as usize;
 
                     Pointer {
-                        rm: RegisterMemory::Memory,
+                        rm: RegisterMemory::Memory(self.effective_segment(Segment::Ds)),
                         offset,
                     }
                 }
-                _ => panic!("unsupported r/m {:#b}", rm),
+                _ => return Err(Exception::UnsupportedModRm(mod_rm)),
             },
             0b01 => match rm {
                 0b011 => {
@@ -504,11 +1325,12 @@ impl Cpu {
                     let offset = (bp.wrapping_add(di).wrapping_add(byte as u16)) as usize;
 
                     Pointer {
-                        rm: RegisterMemory::Memory,
+                        // BP-relative addressing defaults to the stack segment
+                        rm: RegisterMemory::Memory(self.effective_segment(Segment::Ss)),
                         offset,
                     }
                 }
-                _ => panic!("unsupported r/m {:#b}", rm),
+                _ => return Err(Exception::UnsupportedModRm(mod_rm)),
             },
             0b10 => match rm {
                 0b101 => {
@@ -517,7 +1339,7 @@ impl Cpu {
                     let offset = di.wrapping_add(word) as usize;
 
                     Pointer {
-                        rm: RegisterMemory::Memory,
+                        rm: RegisterMemory::Memory(self.effective_segment(Segment::Ds)),
                         offset,
                     }
                 }
@@ -527,17 +1349,17 @@ impl Cpu {
                     let offset = bx.wrapping_add(word) as usize;
 
                     Pointer {
-                        rm: RegisterMemory::Memory,
+                        rm: RegisterMemory::Memory(self.effective_segment(Segment::Ds)),
                         offset,
                     }
                 }
-                _ => panic!("unsupported r/m {:#b}", rm),
+                _ => return Err(Exception::UnsupportedModRm(mod_rm)),
             },
             0b11 => Cpu::get_register(rm),
-            _ => panic!("unsupported mod {:#b}", md),
+            _ => unreachable!(),
         };
 
-        (operand, rg_op)
+        Ok((operand, rg_op))
     }
 
     fn sign_extend(a: u8) -> u16 {
@@ -545,17 +1367,27 @@ impl Cpu {
     }
 
     // arithmetic logic unit
-    fn alu(&mut self, operation: Operation, op1: u16, op2: u16) -> u16 {
+    fn alu(&mut self, operation: Operation, op1: u16, op2: u16, width: Width) -> u16 {
+        let mask = width.mask();
+        let sign_bit = width.sign_bit();
+
+        let op1 = op1 & mask;
+        let op2 = op2 & mask;
+
         let mut result = match operation {
             Operation::Add | Operation::Adc => {
                 let mut add = op2;
 
                 if operation == Operation::Adc && self.cf {
-                    add += 1;
+                    add = add.wrapping_add(1);
                 }
 
-                let result = op1.wrapping_add(add);
-                self.cf = result < op1;
+                let full = op1 as u32 + add as u32;
+                let result = full as u16 & mask;
+
+                self.cf = full & !(mask as u32) != 0;
+                self.af = (op1 ^ op2 ^ result) & 0x10 != 0;
+                self.of = (op1 ^ result) & (op2 ^ result) & sign_bit != 0;
 
                 result
             }
@@ -563,29 +1395,36 @@ impl Cpu {
                 let mut sub = op2;
 
                 if operation == Operation::Sbb && self.cf {
-                    sub += 1;
+                    sub = sub.wrapping_add(1);
                 }
 
-                let result = op1.wrapping_sub(sub);
-                self.cf = result > op1;
+                let full = op1 as i32 - sub as i32;
+                let result = full as u16 & mask;
+
+                self.cf = full < 0;
+                self.af = (op1 ^ op2 ^ result) & 0x10 != 0;
+                self.of = (op1 ^ op2) & (op1 ^ result) & sign_bit != 0;
 
                 result
             }
             Operation::And => {
                 self.cf = false;
+                self.of = false;
                 op1 & op2
             }
             Operation::Or => {
                 self.cf = false;
+                self.of = false;
                 op1 | op2
             }
             Operation::Xor => {
                 self.cf = false;
+                self.of = false;
                 op1 ^ op2
             }
         };
 
-        self.set_flags(&result);
+        self.set_flags(result, width);
 
         // compare doesn't generate a result, it just sets flags
         if operation == Operation::Cmp {
@@ -595,9 +1434,34 @@ impl Cpu {
         result
     }
 
-    fn set_flags(&mut self, value: &u16) {
-        self.zf = *value == 0;
-        self.sf = *value >> 15 == 1;
+    fn set_flags(&mut self, value: u16, width: Width) {
+        let result = value & width.mask();
+
+        self.zf = result == 0;
+        self.sf = result & width.sign_bit() != 0;
+        self.pf = (result as u8).count_ones().is_multiple_of(2);
+    }
+
+    // INC/DEC go through set_flags() like every other result-producing op,
+    // but unlike alu() they must leave CF alone, so AF/OF are worked out
+    // here using the same formulas alu() uses for ADD/SUB rather than
+    // routing through alu() and clobbering the carry flag
+    fn set_incdec_flags(&mut self, op1: u16, result: u16, width: Width, is_inc: bool) {
+        let mask = width.mask();
+        let sign_bit = width.sign_bit();
+
+        let op1 = op1 & mask;
+        let op2 = 1 & mask;
+        let result = result & mask;
+
+        self.af = (op1 ^ op2 ^ result) & 0x10 != 0;
+        self.of = if is_inc {
+            (op1 ^ result) & (op2 ^ result) & sign_bit != 0
+        } else {
+            (op1 ^ op2) & (op1 ^ result) & sign_bit != 0
+        };
+
+        self.set_flags(result, width);
     }
 
     fn jump_short(&mut self, condition: bool) {
@@ -608,35 +1472,280 @@ impl Cpu {
         }
     }
 
-    fn dump_memory(&self) {
-        let mut offset: usize = 0x8000;
+    // -----------------------
+    // interrupts
+    // -----------------------
 
-        for _line in 0..25 {
-            for _char in 0..80 {
-                let mut output = ' ';
+    // bit 1 of FLAGS is always set on real hardware, the undocumented flags
+    // we don't track (TF, DF, IOPL, NT) always read back as 0
+    fn flags_to_u16(&self) -> u16 {
+        let mut flags: u16 = 0b10;
 
-                if self.memory[offset] != 0 {
-                    output = self.memory[offset] as char;
-                }
+        flags |= self.cf as u16;
+        flags |= (self.pf as u16) << 2;
+        flags |= (self.af as u16) << 4;
+        flags |= (self.zf as u16) << 6;
+        flags |= (self.sf as u16) << 7;
+        flags |= (self.intf as u16) << 9;
+        flags |= (self.of as u16) << 11;
 
-                print!("{}", output);
-                offset += 1;
+        flags
+    }
+
+    fn flags_from_u16(&mut self, flags: u16) {
+        self.cf = flags & 1 != 0;
+        self.pf = flags & (1 << 2) != 0;
+        self.af = flags & (1 << 4) != 0;
+        self.zf = flags & (1 << 6) != 0;
+        self.sf = flags & (1 << 7) != 0;
+        self.intf = flags & (1 << 9) != 0;
+        self.of = flags & (1 << 11) != 0;
+    }
+
+    // pushes FLAGS, CS, IP, clears IF, and loads CS:IP from the vector's IVT
+    // entry, so a device (or future raise_interrupt() caller) can route a
+    // hardware interrupt through the same path as INT n
+    fn raise_interrupt(&mut self, vector: u8) {
+        let flags = self.flags_to_u16();
+
+        self.push16(flags);
+        self.push16(self.cs);
+        self.push16(self.ip);
+
+        self.intf = false;
+
+        // the IVT lives at the start of physical memory, 4 bytes per vector:
+        // offset then segment
+        let entry = vector as u16 * 4;
+
+        let ip_lo = self.bus.read8(Cpu::physical_address(0, entry));
+        let ip_hi = self.bus.read8(Cpu::physical_address(0, entry.wrapping_add(1)));
+        let cs_lo = self.bus.read8(Cpu::physical_address(0, entry.wrapping_add(2)));
+        let cs_hi = self.bus.read8(Cpu::physical_address(0, entry.wrapping_add(3)));
+
+        self.ip = Cpu::to16(ip_lo, ip_hi);
+        self.cs = Cpu::to16(cs_lo, cs_hi);
+    }
+
+    // prints the next opcode, the register file, and the flags, for the
+    // single-step debugger in main()
+    fn dump_state(&mut self) {
+        let opcode = self.bus.read8(Cpu::physical_address(self.cs, self.ip));
+
+        println!("ip={:04x} cs={:04x} opcode={:#04x}", self.ip, self.cs, opcode);
+
+        for (name, reg) in ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name, Cpu::get_register(i as u8)))
+        {
+            print!("{}={:04x} ", name, self.read16(&reg));
+        }
+        println!();
+
+        println!("ds={:04x} ss={:04x} es={:04x}", self.ds, self.ss, self.es);
+
+        println!(
+            "cf={} zf={} sf={} of={} af={} pf={} if={}",
+            self.cf as u8,
+            self.zf as u8,
+            self.sf as u8,
+            self.of as u8,
+            self.af as u8,
+            self.pf as u8,
+            self.intf as u8,
+        );
+    }
+
+    // dumps `len` bytes of physical memory starting at `start`, for the "m"
+    // debugger command; addresses wrap at the 20-bit physical space like
+    // every other memory access, instead of indexing the backing array
+    // straight off whatever the user typed
+    fn dump_memory_range(&mut self, start: usize, len: usize) {
+        for i in 0..len {
+            let byte = self.bus.read8((start + i) & 0xfffff);
+
+            print!("{:02x} ", byte);
+
+            if i % 16 == 15 {
+                println!();
             }
-            println!();
+        }
+        println!();
+    }
+}
+
+// reads a command line and handles the debugger's breakpoint commands,
+// returning true once it's time to execute an instruction
+fn debugger_prompt(cpu: &mut Cpu, breakpoints: &mut HashSet<u16>, stepping: &mut bool, quit: &mut bool) -> bool {
+    cpu.dump_state();
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).unwrap();
+
+    match input.trim() {
+        "" | "s" => true,
+        "c" => {
+            *stepping = false;
+            true
+        }
+        "q" => {
+            // run() out at main(), instead of exiting here directly, so
+            // main() still gets a chance to honour --save-state on the way out
+            *quit = true;
+            true
+        }
+        cmd if cmd.starts_with("b ") => {
+            if let Ok(addr) = u16::from_str_radix(cmd[2..].trim_start_matches("0x"), 16) {
+                breakpoints.insert(addr);
+                println!("breakpoint set at {:#06x}", addr);
+            }
+            false
+        }
+        cmd if cmd.starts_with("d ") => {
+            if let Ok(addr) = u16::from_str_radix(cmd[2..].trim_start_matches("0x"), 16) {
+                breakpoints.remove(&addr);
+                println!("breakpoint cleared at {:#06x}", addr);
+            }
+            false
+        }
+        cmd if cmd.starts_with("m ") => {
+            let mut parts = cmd[2..].split_whitespace();
+            let start = parts.next().and_then(|s| usize::from_str_radix(s.trim_start_matches("0x"), 16).ok());
+            let len = parts.next().and_then(|s| usize::from_str_radix(s.trim_start_matches("0x"), 16).ok());
+
+            if let (Some(start), Some(len)) = (start, len) {
+                cpu.dump_memory_range(start, len);
+            }
+            false
+        }
+        _ => {
+            println!("commands: s(tep), c(ontinue), b <addr>, d <addr>, m <start> <len>, q(uit)");
+            false
         }
     }
 }
 
+// returns the value following `flag` on the command line, if present, e.g.
+// for `--load-state snapshot.bin`
+fn arg_value(flag: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+}
+
 fn main() {
     // setup CPU
     let mut cpu = Cpu::new();
 
-    // load program
-    cpu.load_program("codegolf.bin");
+    // --load-state resumes a machine frozen by an earlier --save-state run
+    // instead of starting codegolf.bin from scratch
+    match arg_value("--load-state") {
+        Some(path) => cpu.load_state(&path),
+        None => cpu.load_program("codegolf.bin"),
+    }
+
+    // --save-state freezes the machine to this path once it stops running,
+    // so a later --load-state run can resume it byte-for-byte
+    let save_state_path = arg_value("--save-state");
+
+    // --debug drops into a single-step prompt before every instruction;
+    // breakpoints always drop back into it even when just continuing
+    let mut stepping = std::env::args().any(|arg| arg == "--debug");
+    let mut breakpoints: HashSet<u16> = HashSet::new();
+    let mut quit = false;
+
+    // run until halt, an exception with no IVT vector to recover through,
+    // or the debugger's "q" command
+    loop {
+        if breakpoints.contains(&cpu.ip) {
+            stepping = true;
+        }
+
+        if stepping {
+            while !debugger_prompt(&mut cpu, &mut breakpoints, &mut stepping, &mut quit) {}
+        }
+
+        if quit {
+            break;
+        }
+
+        if let Err(exception) = cpu.run() {
+            match exception.vector() {
+                Some(vector) => cpu.raise_interrupt(vector),
+                None => {
+                    println!("stopped: {:?}", exception);
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Some(path) = save_state_path {
+        cpu.save_state(&path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // boots a tiny program directly into RAM (bypassing load_program(), so
+    // the test doesn't depend on a file on disk), runs it to Halted, and
+    // returns the final register/flag snapshot
+    fn run_to_halt(cpu: &mut Cpu) {
+        loop {
+            match cpu.run() {
+                Ok(()) => {}
+                Err(Exception::Halted) => break,
+                Err(exception) => panic!("unexpected exception: {:?}", exception),
+            }
+        }
+    }
+
+    #[test]
+    fn save_state_round_trip_resumes_execution_identically() {
+        // Cpu carries the full 1MB RAM array by value, which overflows the
+        // default test thread stack once a few instances are alive at
+        // once in an unoptimized build; give this test its own large stack
+        std::thread::Builder::new()
+            .stack_size(64 * 1024 * 1024)
+            .spawn(run_save_state_round_trip)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    fn run_save_state_round_trip() {
+        // mov ax, 1 ; inc ax ; mov bx, 2 ; inc bx ; hlt
+        let program: [u8; 9] = [0xb8, 0x01, 0x00, 0x40, 0xbb, 0x02, 0x00, 0x43, 0xf4];
+
+        // runs straight through, with no snapshot/restore in the middle
+        let mut uninterrupted = Cpu::new();
+        uninterrupted.bus.load(0, &program);
+        run_to_halt(&mut uninterrupted);
+
+        // runs the first two instructions, snapshots, then resumes a fresh
+        // Cpu from that snapshot for the rest
+        let mut original = Cpu::new();
+        original.bus.load(0, &program);
+        original.run().unwrap(); // mov ax, 1
+        original.run().unwrap(); // inc ax
 
-    // run until halt
-    while cpu.run() {}
+        let path = std::env::temp_dir().join(format!("partial8086-test-{}.sav", std::process::id()));
+        original.save_state(path.to_str().unwrap());
 
-    // dump memory
-    cpu.dump_memory();
+        let mut restored = Cpu::new();
+        restored.load_state(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        run_to_halt(&mut restored);
+
+        assert_eq!(restored.ip, uninterrupted.ip);
+        assert_eq!(restored.regs, uninterrupted.regs);
+        assert_eq!(restored.flags_to_u16(), uninterrupted.flags_to_u16());
+    }
 }